@@ -1,35 +1,196 @@
-use std::{collections::HashMap, fs::Permissions, os::unix::fs::PermissionsExt, path::PathBuf, sync::Arc};
+use std::{
+    collections::HashMap,
+    fs::Permissions,
+    io::{Read as _, Seek as _},
+    os::unix::fs::PermissionsExt,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
 
 use anyhow::{Context as _, bail};
 use axum::{
     Json, Router,
     body::Body,
-    extract::{DefaultBodyLimit, Path, State},
+    extract::{DefaultBodyLimit, Path, Query, State},
     http::{Response, StatusCode},
     response::IntoResponse,
     routing::{get, post},
 };
 use base64ct::Encoding;
+use bytes::Bytes;
 use clap::Parser;
+use futures::stream::{self, StreamExt as _};
 use md5::{Digest as _, Md5};
-use serde::Deserialize;
-use subprocess::{Popen, PopenConfig};
-use tokio::sync::{
-    RwLock,
-    broadcast::{self, Sender},
+use serde::{Deserialize, Serialize};
+use subprocess::{Popen, PopenConfig, Redirection};
+use tokio::{
+    io::{AsyncReadExt as _, AsyncSeekExt as _},
+    sync::{
+        RwLock,
+        broadcast::{self, Sender},
+    },
 };
+use tokio_stream::wrappers::{BroadcastStream, errors::BroadcastStreamRecvError};
+use tokio_util::io::{ReaderStream, StreamReader, SyncIoBridge};
 use uuid::Uuid;
+use walkdir::WalkDir;
+
+const RUN_OUTPUT_CHUNK_SIZE: usize = 8 * 1024;
+/// How many finished runs to keep in the `runs` tree before the oldest are evicted.
+const MAX_COMPLETED_RUNS: usize = 500;
+/// How long a finished run (and its buffered output) is kept around before eviction.
+const MAX_RUN_AGE_SECS: u64 = 7 * 24 * 60 * 60;
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 enum RunStatus {
     Running,
     Success,
     Fail,
 }
 
+/// Durable record for a single run, persisted to `sled` keyed by run UUID so job history
+/// survives a restart.
+#[derive(Clone, Serialize, Deserialize)]
+struct RunRecord {
+    cmd: Vec<String>,
+    workdir: String,
+    status: RunStatus,
+    exit_code: Option<i32>,
+    started_at: u64,
+    finished_at: Option<u64>,
+}
+
+fn now_unix() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn exit_code_of(status: &subprocess::ExitStatus) -> Option<i32> {
+    use subprocess::ExitStatus::*;
+    match status {
+        Exited(code) => Some(*code as i32),
+        Signaled(signal) => Some(-(*signal as i32)),
+        Other(code) => Some(*code),
+        Undetermined => None,
+    }
+}
+
+fn save_run_record(db: &sled::Db, id: &str, record: &RunRecord) {
+    match serde_json::to_vec(record) {
+        Ok(bytes) => {
+            let _ = db.insert(id.as_bytes(), bytes);
+        }
+        Err(e) => log::error!("[{id}] Failed to serialize run record: {e:?}"),
+    }
+}
+
+/// Reloads persisted run metadata on startup. Any run still marked `Running` belonged to a
+/// previous process instance that is gone, so it's finalized as failed.
+fn reload_runs(db: &sled::Db) -> HashMap<String, RunStatus> {
+    let mut runs = HashMap::new();
+    for entry in db.iter() {
+        let Ok((key, value)) = entry else { continue };
+        let Ok(mut record) = serde_json::from_slice::<RunRecord>(&value) else {
+            continue;
+        };
+
+        if matches!(record.status, RunStatus::Running) {
+            record.status = RunStatus::Fail;
+            record.finished_at = Some(now_unix());
+            let _ = db.insert(key.clone(), serde_json::to_vec(&record).unwrap_or_default());
+        }
+
+        runs.insert(String::from_utf8_lossy(&key).into_owned(), record.status);
+    }
+    runs
+}
+
+/// Prunes completed runs once they're older than `MAX_RUN_AGE_SECS` or there are more of them
+/// than `MAX_COMPLETED_RUNS`, dropping their persisted record, status cache entry, and
+/// buffered output together.
+async fn evict_old_runs(state: &AppState) {
+    let mut completed = Vec::new();
+    for entry in state.db.iter() {
+        let Ok((key, value)) = entry else { continue };
+        if let Ok(record) = serde_json::from_slice::<RunRecord>(&value) {
+            if let Some(finished_at) = record.finished_at {
+                completed.push((String::from_utf8_lossy(&key).into_owned(), finished_at));
+            }
+        }
+    }
+    completed.sort_by_key(|(_, finished_at)| *finished_at);
+
+    let now = now_unix();
+    let mut to_evict: Vec<String> = Vec::new();
+    if completed.len() > MAX_COMPLETED_RUNS {
+        let excess = completed.len() - MAX_COMPLETED_RUNS;
+        to_evict.extend(completed.drain(..excess).map(|(id, _)| id));
+    }
+    to_evict.extend(
+        completed
+            .into_iter()
+            .filter(|(_, finished_at)| now.saturating_sub(*finished_at) > MAX_RUN_AGE_SECS)
+            .map(|(id, _)| id),
+    );
+
+    for id in to_evict {
+        let _ = state.db.remove(id.as_bytes());
+        state.runs.write().await.remove(&id);
+        state.run_outputs.write().await.remove(&id);
+    }
+}
+
+/// Buffered + live output for a single run, so `/run-output` can replay history and then
+/// keep streaming while the process is still going.
+#[derive(Clone)]
+struct RunOutput {
+    log: Arc<Mutex<Vec<u8>>>,
+    sender: Arc<Mutex<Option<Sender<Bytes>>>>,
+}
+
+impl RunOutput {
+    fn new() -> Self {
+        let (tx, _) = broadcast::channel(1024);
+        Self {
+            log: Arc::new(Mutex::new(Vec::new())),
+            sender: Arc::new(Mutex::new(Some(tx))),
+        }
+    }
+
+    fn push(&self, chunk: &[u8]) {
+        // Hold `log` across the send too: `snapshot_and_subscribe` also locks `log` first, so
+        // this makes extend+send mutually exclusive with snapshot+subscribe and rules out a
+        // chunk being delivered both in the replay buffer and again on the live tail.
+        let mut log = self.log.lock().unwrap();
+        log.extend_from_slice(chunk);
+        if let Some(tx) = self.sender.lock().unwrap().as_ref() {
+            let _ = tx.send(Bytes::copy_from_slice(chunk));
+        }
+    }
+
+    fn finish(&self) {
+        self.sender.lock().unwrap().take();
+    }
+
+    /// Snapshots the buffered log and subscribes to live output as one atomic step. `push`
+    /// always locks `log` before `sender`, so holding `log` across the subscribe here rules
+    /// out a chunk landing in the gap between the snapshot and the subscription.
+    fn snapshot_and_subscribe(&self) -> (Bytes, Option<broadcast::Receiver<Bytes>>) {
+        let log = self.log.lock().unwrap();
+        let buffered = Bytes::copy_from_slice(&log);
+        let live_rx = self.sender.lock().unwrap().as_ref().map(Sender::subscribe);
+        (buffered, live_rx)
+    }
+}
+
 #[derive(Clone)]
 struct AppState {
     runs: Arc<RwLock<HashMap<String, RunStatus>>>,
+    run_outputs: Arc<RwLock<HashMap<String, RunOutput>>>,
+    db: sled::Db,
 
     run_status_sender: Arc<Sender<()>>,
 }
@@ -50,7 +211,18 @@ async fn run(State(state): State<AppState>, Json(request): Json<RunRequest>) ->
     let workdir = PathBuf::from(&request.workdir);
     let _ = std::fs::create_dir_all(&workdir);
 
+    let output = RunOutput::new();
+    let mut record = RunRecord {
+        cmd: request.cmd.clone(),
+        workdir: request.workdir.clone(),
+        status: RunStatus::Running,
+        exit_code: None,
+        started_at: now_unix(),
+        finished_at: None,
+    };
+    save_run_record(&state.db, &uuid, &record);
     state.runs.write().await.insert(uuid.clone(), RunStatus::Running);
+    state.run_outputs.write().await.insert(uuid.clone(), output.clone());
     log::info!("[{uuid}] Started job {:?} at {}", request.cmd, request.workdir);
 
     let uuid_func = uuid.clone();
@@ -58,12 +230,20 @@ async fn run(State(state): State<AppState>, Json(request): Json<RunRequest>) ->
         let request = request;
         let state = state;
         let uuid = uuid_func;
+        let mut record = record;
+        let output = output;
         let cmd = request.cmd.clone();
-        let res = tokio::task::spawn_blocking(move || {
+        let output_func = output.clone();
+        let uuid_blocking = uuid.clone();
+        let res = tokio::task::spawn_blocking(move || -> anyhow::Result<subprocess::ExitStatus> {
+            let output = output_func;
+            let uuid = uuid_blocking;
             let mut p = match Popen::create(
                 &cmd,
                 PopenConfig {
                     cwd: Some(workdir.into()),
+                    stdout: Redirection::Pipe,
+                    stderr: Redirection::Merge,
                     ..Default::default()
                 },
             ) {
@@ -72,32 +252,100 @@ async fn run(State(state): State<AppState>, Json(request): Json<RunRequest>) ->
                     bail!("Error when starting process: {e:?}");
                 }
             };
-            p.wait().ok();
-            let exit_status = p.poll().with_context(|| "Can't get exit status")?;
-            if !exit_status.success() {
-                anyhow::bail!("Exit status {:?}", exit_status);
+
+            let mut stdout = p.stdout.take().context("child has no stdout pipe")?;
+            let mut buf = [0u8; RUN_OUTPUT_CHUNK_SIZE];
+            loop {
+                match stdout.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => output.push(&buf[..n]),
+                    Err(e) => {
+                        log::warn!("[{uuid}] Error reading run output: {e}");
+                        break;
+                    }
+                }
             }
+            drop(stdout);
 
-            Ok(())
+            p.wait().ok();
+            p.poll().with_context(|| "Can't get exit status")
         })
         .await;
+        output.finish();
 
-        match res {
-            Ok(_) => {
-                log::info!("[{uuid}] Succeeded job {:?} at {}", request.cmd, request.workdir,);
-                state.runs.write().await.insert(uuid, RunStatus::Success);
+        let status = match &res {
+            Ok(Ok(exit_status)) if exit_status.success() => {
+                log::info!("[{uuid}] Succeeded job {:?} at {}", request.cmd, request.workdir);
+                RunStatus::Success
+            }
+            Ok(Ok(exit_status)) => {
+                log::error!(
+                    "[{uuid}] Job {:?} at {} exited with {:?}",
+                    request.cmd,
+                    request.workdir,
+                    exit_status
+                );
+                RunStatus::Fail
+            }
+            Ok(Err(e)) => {
+                log::error!("[{uuid}] Failed job {:?} at {}: {e:?}", request.cmd, request.workdir);
+                RunStatus::Fail
             }
             Err(e) => {
-                log::error!("[{uuid}] Failed job {:?} at {}: {e:?}", request.cmd, request.workdir,);
-                state.runs.write().await.insert(uuid, RunStatus::Fail);
+                log::error!("[{uuid}] Panicked running job {:?} at {}: {e:?}", request.cmd, request.workdir);
+                RunStatus::Fail
             }
         };
+
+        record.status = status;
+        record.exit_code = res.as_ref().ok().and_then(|r| r.as_ref().ok()).and_then(exit_code_of);
+        record.finished_at = Some(now_unix());
+        save_run_record(&state.db, &uuid, &record);
+
+        state.runs.write().await.insert(uuid.clone(), status);
+        evict_old_runs(&state).await;
         let _ = state.run_status_sender.send(());
     });
 
     Ok(uuid)
 }
 
+async fn run_output(State(state): State<AppState>, Path(id): Path<String>) -> Result<Response<Body>, AppError> {
+    let output = state
+        .run_outputs
+        .read()
+        .await
+        .get(&id)
+        .cloned()
+        .ok_or_else(|| anyhow::anyhow!("Run {id} not found"))?;
+
+    let (buffered, live_rx) = output.snapshot_and_subscribe();
+
+    let head = stream::once(async move { Ok::<_, std::io::Error>(buffered) });
+    let body = match live_rx {
+        Some(rx) => {
+            let tail = BroadcastStream::new(rx).filter_map(move |item| {
+                let id = id.clone();
+                async move {
+                    match item {
+                        Ok(chunk) => Some(chunk),
+                        Err(BroadcastStreamRecvError::Lagged(n)) => {
+                            log::warn!("[{id}] Dropped {n} run-output message(s): reader fell behind");
+                            None
+                        }
+                    }
+                }
+            });
+            Body::from_stream(head.chain(tail.map(Ok)))
+        }
+        None => Body::from_stream(head),
+    };
+
+    Ok(Response::builder()
+        .header("content-type", "application/octet-stream")
+        .body(body)?)
+}
+
 async fn wait_run(State(state): State<AppState>, Path(id): Path<String>) -> Result<&'static str, AppError> {
     let mut ch = state.run_status_sender.subscribe();
     loop {
@@ -118,6 +366,37 @@ async fn wait_run(State(state): State<AppState>, Path(id): Path<String>) -> Resu
     }
 }
 
+#[derive(Serialize)]
+struct RunListEntry {
+    id: String,
+    cmd: Vec<String>,
+    workdir: String,
+    status: RunStatus,
+    exit_code: Option<i32>,
+    started_at: u64,
+    finished_at: Option<u64>,
+}
+
+async fn list_runs(State(state): State<AppState>) -> Result<Json<Vec<RunListEntry>>, AppError> {
+    let mut entries = Vec::new();
+    for item in state.db.iter() {
+        let (key, value) = item?;
+        let record: RunRecord = serde_json::from_slice(&value)?;
+        entries.push(RunListEntry {
+            id: String::from_utf8_lossy(&key).into_owned(),
+            cmd: record.cmd,
+            workdir: record.workdir,
+            status: record.status,
+            exit_code: record.exit_code,
+            started_at: record.started_at,
+            finished_at: record.finished_at,
+        });
+    }
+    entries.sort_by(|a, b| b.started_at.cmp(&a.started_at));
+
+    Ok(Json(entries))
+}
+
 #[derive(Deserialize)]
 struct OfferFilesRequest {
     workdir: String,
@@ -141,6 +420,101 @@ async fn offer_files(Json(request): Json<OfferFilesRequest>) -> Json<Vec<String>
     Json(result)
 }
 
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum EntryFileType {
+    File,
+    Dir,
+    Symlink,
+}
+
+#[derive(Serialize)]
+struct DirEntryInfo {
+    path: String,
+    file_type: EntryFileType,
+    len: u64,
+    executable: bool,
+    modified: Option<u64>,
+}
+
+fn unix_timestamp(metadata: &std::fs::Metadata) -> Option<u64> {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+}
+
+#[derive(Deserialize)]
+struct ListDirRequest {
+    workdir: String,
+    path: String,
+    depth: usize,
+}
+
+async fn list_dir(Json(request): Json<ListDirRequest>) -> Result<Json<Vec<DirEntryInfo>>, AppError> {
+    let workdir = PathBuf::from(&request.workdir);
+    let root = workdir.join(&request.path);
+
+    let mut entries = Vec::new();
+    for entry in WalkDir::new(&root).min_depth(1).max_depth(request.depth) {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        let file_type = if metadata.file_type().is_symlink() {
+            EntryFileType::Symlink
+        } else if metadata.is_dir() {
+            EntryFileType::Dir
+        } else {
+            EntryFileType::File
+        };
+
+        entries.push(DirEntryInfo {
+            path: entry
+                .path()
+                .strip_prefix(&workdir)
+                .unwrap_or(entry.path())
+                .to_string_lossy()
+                .into_owned(),
+            file_type,
+            len: metadata.len(),
+            executable: metadata.permissions().mode() & 0o111 != 0,
+            modified: unix_timestamp(&metadata),
+        });
+    }
+
+    Ok(Json(entries))
+}
+
+#[derive(Deserialize)]
+struct StatRequest {
+    workdir: String,
+    path: String,
+}
+
+#[derive(Serialize)]
+struct StatResponse {
+    len: u64,
+    mode: u32,
+    modified: Option<u64>,
+    is_dir: bool,
+    is_file: bool,
+    is_symlink: bool,
+}
+
+async fn stat(Json(request): Json<StatRequest>) -> Result<Json<StatResponse>, AppError> {
+    let path = PathBuf::from(&request.workdir).join(&request.path);
+    let metadata = std::fs::symlink_metadata(&path)?;
+
+    Ok(Json(StatResponse {
+        len: metadata.len(),
+        mode: metadata.permissions().mode(),
+        modified: unix_timestamp(&metadata),
+        is_dir: metadata.is_dir(),
+        is_file: metadata.is_file(),
+        is_symlink: metadata.file_type().is_symlink(),
+    }))
+}
+
 #[derive(Deserialize)]
 struct FileInfo {
     data: String,
@@ -167,23 +541,150 @@ async fn send_files(Json(request): Json<SendFilesRequest>) {
     }
 }
 
+#[derive(Deserialize)]
+struct SendFilesTarQuery {
+    workdir: String,
+}
+
+/// Streaming counterpart to `send_files`: the request body is a gzip-compressed tar stream,
+/// unpacked straight into `workdir` without ever buffering the whole payload in memory.
+async fn send_files_tar(
+    Query(query): Query<SendFilesTarQuery>,
+    body: Body,
+) -> Result<(), AppError> {
+    let workdir = PathBuf::from(&query.workdir);
+    std::fs::create_dir_all(&workdir)?;
+    let canonical_workdir = workdir.canonicalize()?;
+
+    let stream = body.into_data_stream().map(|chunk| chunk.map_err(std::io::Error::other));
+    let reader = SyncIoBridge::new(StreamReader::new(stream));
+
+    tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+        let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(reader));
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let relative_path = entry.path()?.into_owned();
+            if relative_path.components().any(|c| {
+                !matches!(c, std::path::Component::Normal(_) | std::path::Component::CurDir)
+            }) {
+                bail!("Tar entry {} escapes workdir", relative_path.display());
+            }
+
+            let executable = entry.header().mode()? & 0o111 != 0;
+            let dest = workdir.join(&relative_path);
+
+            // Resolve the destination's parent (following any symlinks planted by an earlier
+            // entry) and confirm it's still inside `workdir` before writing anything there.
+            let parent = dest.parent().context("tar entry has no parent path")?;
+            let mut existing = parent;
+            while std::fs::symlink_metadata(existing).is_err() {
+                existing = existing.parent().context("tar entry has no existing ancestor")?;
+            }
+            if !existing.canonicalize()?.starts_with(&canonical_workdir) {
+                bail!("Tar entry {} escapes workdir", relative_path.display());
+            }
+            std::fs::create_dir_all(parent)?;
+            let canonical_parent = parent.canonicalize()?;
+            if !canonical_parent.starts_with(&canonical_workdir) {
+                bail!("Tar entry {} escapes workdir", relative_path.display());
+            }
+
+            // An earlier entry may have planted a symlink at this exact path; clear it first
+            // so `unpack` always creates `dest` fresh instead of writing through it.
+            match std::fs::symlink_metadata(&dest) {
+                Ok(meta) if meta.is_dir() => std::fs::remove_dir_all(&dest)?,
+                Ok(_) => std::fs::remove_file(&dest)?,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+
+            entry.unpack(&dest)?;
+            if executable {
+                std::fs::set_permissions(&dest, Permissions::from_mode(0o777))?;
+            }
+        }
+        Ok(())
+    })
+    .await??;
+
+    Ok(())
+}
+
 #[derive(Deserialize)]
 struct GetFileRequest {
     workdir: String,
     path: String,
+    #[serde(default)]
+    offset: Option<u64>,
+    #[serde(default)]
+    length: Option<u64>,
 }
 
-async fn get_file(Json(request): Json<GetFileRequest>) -> String {
+async fn get_file(Json(request): Json<GetFileRequest>) -> Result<String, AppError> {
     let workdir = PathBuf::from(&request.workdir);
     let path = workdir.join(request.path);
-    let bytes = std::fs::read(&path).unwrap();
-    base64ct::Base64::encode_string(&bytes)
+
+    let bytes = if request.offset.is_some() || request.length.is_some() {
+        let mut file = std::fs::File::open(&path)?;
+        file.seek(std::io::SeekFrom::Start(request.offset.unwrap_or(0)))?;
+        match request.length {
+            Some(length) => {
+                let mut buf = Vec::new();
+                file.take(length).read_to_end(&mut buf)?;
+                buf
+            }
+            None => {
+                let mut buf = Vec::new();
+                file.read_to_end(&mut buf)?;
+                buf
+            }
+        }
+    } else {
+        std::fs::read(&path)?
+    };
+
+    Ok(base64ct::Base64::encode_string(&bytes))
+}
+
+#[derive(Deserialize)]
+struct GetFileRawQuery {
+    workdir: String,
+    path: String,
+    #[serde(default)]
+    offset: Option<u64>,
+    #[serde(default)]
+    length: Option<u64>,
+}
+
+/// Streams a file straight from disk instead of buffering + base64-encoding it, so large
+/// artifacts can be paged through with `offset`/`length` at constant memory.
+async fn get_file_raw(Query(query): Query<GetFileRawQuery>) -> Result<Response<Body>, AppError> {
+    let path = PathBuf::from(&query.workdir).join(&query.path);
+    let mut file = tokio::fs::File::open(&path).await?;
+    let total_len = file.metadata().await?.len();
+
+    if let Some(offset) = query.offset {
+        file.seek(std::io::SeekFrom::Start(offset)).await?;
+    }
+
+    let body = match query.length {
+        Some(length) => Body::from_stream(ReaderStream::new(file.take(length))),
+        None => Body::from_stream(ReaderStream::new(file)),
+    };
+
+    Ok(Response::builder()
+        .header("content-type", "application/octet-stream")
+        .header("x-file-size", total_len.to_string())
+        .body(body)?)
 }
 
 #[derive(Parser)]
 struct Args {
     #[arg(short, long)]
     port: u16,
+
+    #[arg(long, default_value = "runs.db")]
+    db_path: PathBuf,
 }
 
 #[tokio::main]
@@ -191,17 +692,29 @@ async fn main() {
     let args = Args::parse();
     env_logger::init();
 
+    let db = sled::open(&args.db_path).expect("failed to open run database");
+    let runs = reload_runs(&db);
+    log::info!("Reloaded {} run(s) from {}", runs.len(), args.db_path.display());
+
     let state = AppState {
-        runs: Arc::new(RwLock::new(HashMap::default())),
+        runs: Arc::new(RwLock::new(runs)),
+        run_outputs: Arc::new(RwLock::new(HashMap::default())),
+        db,
         run_status_sender: Arc::new(broadcast::channel(1).0),
     };
     let app = Router::<AppState>::new()
         .route("/ping", get(|| async { "pong" }))
         .route("/run", post(run))
         .route("/wait-run/{id}", get(wait_run))
+        .route("/run-output/{id}", get(run_output))
+        .route("/runs", get(list_runs))
         .route("/offer-files", post(offer_files))
+        .route("/list-dir", post(list_dir))
+        .route("/stat", post(stat))
         .route("/send-files", post(send_files))
+        .route("/send-files-tar", post(send_files_tar))
         .route("/get-file", post(get_file))
+        .route("/get-file-raw", get(get_file_raw))
         .with_state(state)
         .layer(DefaultBodyLimit::max(1usize << 30));
 